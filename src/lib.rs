@@ -0,0 +1,171 @@
+//! Platform-agnostic software buffering: convert a `&[u32]` of pixels into whatever the windowing
+//! system underneath needs to see to put them on screen.
+//!
+//! Each platform module exposes its own handle type and is picked based on which
+//! `raw_window_handle`/`raw_display_handle` variant the caller hands us; see [`Impl::new`].
+
+use std::error::Error;
+use std::fmt;
+
+use raw_window_handle::{RawDisplayHandle, RawWindowHandle};
+
+#[cfg(all(unix, not(target_os = "macos")))]
+mod pixel_format;
+#[cfg(all(unix, not(target_os = "macos")))]
+mod x11;
+#[cfg(all(unix, not(target_os = "macos")))]
+mod xcb;
+
+/// An error that can occur while setting up or using a drawing context.
+#[derive(Debug)]
+pub enum SwBufError {
+    /// The windowing system handle passed to us was incomplete, and we can't recover the
+    /// information it's missing.
+    IncompleteDisplayHandle,
+
+    /// The window handle passed to us was incomplete.
+    IncompleteWindowHandle,
+
+    /// The combination of window and display handles we were given isn't one we support.
+    Unsupported,
+
+    /// Something went wrong talking to the platform's windowing system.
+    PlatformError(Option<String>, Option<Box<dyn Error>>),
+}
+
+impl fmt::Display for SwBufError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IncompleteDisplayHandle => write!(f, "the display handle was incomplete"),
+            Self::IncompleteWindowHandle => write!(f, "the window handle was incomplete"),
+            Self::Unsupported => write!(f, "this combination of window and display handles is not supported"),
+            Self::PlatformError(msg, _) => match msg {
+                Some(msg) => write!(f, "platform error: {msg}"),
+                None => write!(f, "platform error"),
+            },
+        }
+    }
+}
+
+impl Error for SwBufError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::PlatformError(_, Some(e)) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// The platform-specific backend picked by [`Impl::new`].
+enum Impl {
+    #[cfg(all(unix, not(target_os = "macos")))]
+    X11(x11::X11Impl),
+    #[cfg(all(unix, not(target_os = "macos")))]
+    Xcb(xcb::XcbImpl),
+}
+
+impl Impl {
+    /// Pick and initialize a backend for the given window/display handle pair.
+    ///
+    /// # Safety
+    ///
+    /// Both handles must be valid, and must outlive the returned `Impl`.
+    unsafe fn new(
+        raw_window_handle: RawWindowHandle,
+        raw_display_handle: RawDisplayHandle,
+    ) -> Result<Self, SwBufError> {
+        match (raw_window_handle, raw_display_handle) {
+            #[cfg(all(unix, not(target_os = "macos")))]
+            (RawWindowHandle::Xlib(window_handle), RawDisplayHandle::Xlib(display_handle)) => {
+                Ok(Self::X11(x11::X11Impl::new(window_handle, display_handle)?))
+            }
+            #[cfg(all(unix, not(target_os = "macos")))]
+            (RawWindowHandle::Xcb(window_handle), RawDisplayHandle::Xcb(display_handle)) => {
+                Ok(Self::Xcb(xcb::XcbImpl::new(window_handle, display_handle)?))
+            }
+            _ => Err(SwBufError::Unsupported),
+        }
+    }
+
+    unsafe fn set_buffer(&mut self, buffer: &[u32], width: u16, height: u16) {
+        match self {
+            #[cfg(all(unix, not(target_os = "macos")))]
+            Self::X11(imp) => imp.set_buffer(buffer, width, height),
+            #[cfg(all(unix, not(target_os = "macos")))]
+            Self::Xcb(imp) => imp.set_buffer(buffer, width, height),
+        }
+    }
+
+    /// Enable or disable drawing into a backing pixmap instead of the window directly. Only
+    /// supported on the Xlib backend for now; a no-op elsewhere.
+    fn set_pixmap_mode(&mut self, enabled: bool) {
+        match self {
+            #[cfg(all(unix, not(target_os = "macos")))]
+            Self::X11(imp) => imp.set_pixmap_mode(enabled),
+            #[cfg(all(unix, not(target_os = "macos")))]
+            Self::Xcb(_) => {}
+        }
+    }
+
+    /// Repaint the window from the backing pixmap, if pixmap mode is enabled. Only supported on
+    /// the Xlib backend for now; a no-op elsewhere.
+    unsafe fn redraw(&mut self) {
+        match self {
+            #[cfg(all(unix, not(target_os = "macos")))]
+            Self::X11(imp) => imp.redraw(),
+            #[cfg(all(unix, not(target_os = "macos")))]
+            Self::Xcb(_) => {}
+        }
+    }
+}
+
+/// A drawing context that converts a buffer of pixels into whatever the windowing system
+/// underneath needs to see to put them on screen.
+pub struct GraphicsContext {
+    imp: Impl,
+}
+
+impl GraphicsContext {
+    /// Create a new `GraphicsContext` for the given window/display handle pair.
+    ///
+    /// # Safety
+    ///
+    /// Both handles must be valid, and must outlive the returned `GraphicsContext`.
+    pub unsafe fn new(
+        raw_window_handle: RawWindowHandle,
+        raw_display_handle: RawDisplayHandle,
+    ) -> Result<Self, SwBufError> {
+        Ok(Self {
+            imp: Impl::new(raw_window_handle, raw_display_handle)?,
+        })
+    }
+
+    /// Draw a buffer of `width * height` pixels, in `0x00RRGGBB` order, to the window.
+    ///
+    /// # Safety
+    ///
+    /// `buffer` must contain at least `width * height` pixels, and the window/display handles
+    /// passed to [`Self::new`] must still be valid.
+    pub unsafe fn set_buffer(&mut self, buffer: &[u32], width: u16, height: u16) {
+        self.imp.set_buffer(buffer, width, height);
+    }
+
+    /// Enable or disable drawing into a backing pixmap instead of the window directly, so that
+    /// the window can be repainted from it later with [`Self::redraw`] (e.g. in response to an
+    /// `Expose` event) without the caller resubmitting a buffer.
+    ///
+    /// Currently only has an effect on the Xlib backend.
+    pub fn set_pixmap_mode(&mut self, enabled: bool) {
+        self.imp.set_pixmap_mode(enabled);
+    }
+
+    /// Repaint the window from the backing pixmap enabled with [`Self::set_pixmap_mode`]. Call
+    /// this from the caller's own event loop on `Expose`.
+    ///
+    /// # Safety
+    ///
+    /// The window/display handles passed to [`Self::new`] must still be valid.
+    pub unsafe fn redraw(&mut self) {
+        self.imp.redraw();
+    }
+}
@@ -0,0 +1,237 @@
+//! Converting a caller-supplied 32-bit BGRA buffer into whatever layout a window's actual
+//! visual/depth needs, shared between the Xlib and XCB backends.
+
+/// How to turn a caller-supplied 32-bit BGRA pixel into bytes in a window's actual visual.
+pub(crate) struct PixelFormat {
+    /// The window's visual is already 32-bit little-endian BGRA, so buffers can be handed to
+    /// the server as-is with no repacking.
+    native: bool,
+
+    /// Bytes used per pixel on the wire (1, 2, or 4).
+    bytes_per_pixel: u8,
+
+    /// The server's scanline pad for this depth, in bits (8, 16, or 32): each row of an image
+    /// sent to the server — over SHM or the wire — must be padded out to a multiple of this
+    /// many bits, which can exceed `width * bytes_per_pixel()` once packed.
+    scanline_pad: u32,
+
+    red: ChannelFormat,
+    green: ChannelFormat,
+    blue: ChannelFormat,
+}
+
+/// Where one 8-bit colour channel lives within a packed pixel.
+struct ChannelFormat {
+    /// The number of bits to shift a sample left by once it's been placed in the low bits.
+    shift: u32,
+
+    /// The number of bits available for this channel, e.g. 5 for the red channel of RGB565.
+    bits: u32,
+}
+
+impl ChannelFormat {
+    /// Derive a channel's position and width from its mask, e.g. `0xf800` for RGB565 red.
+    fn from_mask(mask: u32) -> Self {
+        Self {
+            shift: mask.trailing_zeros(),
+            bits: mask.count_ones(),
+        }
+    }
+
+    /// Downsample an 8-bit channel sample to this channel's width and shift it into place.
+    fn pack(&self, sample8: u32) -> u32 {
+        if self.bits == 0 {
+            // A zero mask (e.g. a palette-based visual with no direct RGB channels) has no
+            // well-defined shift; contribute nothing rather than shifting by the bit width.
+            return 0;
+        }
+        (sample8 >> (8 - self.bits.min(8))) << self.shift
+    }
+}
+
+impl PixelFormat {
+    /// Inspect a visual's channel masks and its depth to work out how pixels need to be
+    /// repacked for this window, if at all. `scanline_pad` is the server's row-padding
+    /// requirement for this depth's pixmap format (see the backend's `XListPixmapFormats` /
+    /// `pixmap_formats` lookup), in bits.
+    pub(crate) fn detect(red_mask: u32, green_mask: u32, blue_mask: u32, depth: u32, scanline_pad: u32) -> Self {
+        let red = ChannelFormat::from_mask(red_mask);
+        let green = ChannelFormat::from_mask(green_mask);
+        let blue = ChannelFormat::from_mask(blue_mask);
+
+        // 24-bit TrueColor visuals are, in practice, always packed into 32-bit words on the
+        // wire; anything shallower keeps to a byte or two per pixel.
+        let bytes_per_pixel: u8 = match depth {
+            1..=8 => 1,
+            9..=16 => 2,
+            _ => 4,
+        };
+
+        let native = bytes_per_pixel == 4
+            && red.shift == 16
+            && red.bits == 8
+            && green.shift == 8
+            && green.bits == 8
+            && blue.shift == 0
+            && blue.bits == 8;
+
+        Self {
+            native,
+            bytes_per_pixel,
+            scanline_pad,
+            red,
+            green,
+            blue,
+        }
+    }
+
+    /// Whether buffers can be handed to the server as-is, with no repacking.
+    pub(crate) fn is_native(&self) -> bool {
+        self.native
+    }
+
+    /// Bytes used per pixel on the wire.
+    pub(crate) fn bytes_per_pixel(&self) -> u8 {
+        self.bytes_per_pixel
+    }
+
+    /// The number of bytes one scanline of `width` pixels occupies once padded out to the
+    /// server's scanline pad for this depth. This is the stride a server-read image (SHM or
+    /// `shm_put_image`'s segment) must use; it isn't necessarily `width * bytes_per_pixel()`.
+    pub(crate) fn stride(&self, width: usize) -> usize {
+        let row_bits = width * self.bytes_per_pixel as usize * 8;
+        let pad = self.scanline_pad.max(8) as usize;
+        (row_bits + pad - 1) / pad * (pad / 8)
+    }
+
+    /// Repack one 32-bit BGRA pixel (`0x00RRGGBB`) into this format, returned in the low bytes
+    /// of the result.
+    fn pack(&self, pixel: u32) -> u32 {
+        let r = (pixel >> 16) & 0xff;
+        let g = (pixel >> 8) & 0xff;
+        let b = pixel & 0xff;
+        self.red.pack(r) | self.green.pack(g) | self.blue.pack(b)
+    }
+
+    /// Write a `width` x `height` image (`buffer`, row-major) to `dst` in this pixel format,
+    /// one scanline at a time, with each row starting `dst_stride` bytes after the last.
+    ///
+    /// `dst_stride` is caller-controlled rather than derived from `width` here: a server-read
+    /// image (SHM, or `shm_put_image`'s segment) needs [`Self::stride`]'s padded value, while a
+    /// client-owned buffer the caller reformats itself (e.g. `XCreateImage`'s own `data`, which
+    /// Xlib repacks for the wire) can use a tightly packed `width * bytes_per_pixel()`.
+    ///
+    /// # Safety
+    ///
+    /// `dst` must be valid for `dst_stride * height` bytes, and `buffer` must hold at least
+    /// `width * height` pixels.
+    pub(crate) unsafe fn write(&self, buffer: &[u32], width: usize, height: usize, dst_stride: usize, dst: *mut u8) {
+        let bpp = self.bytes_per_pixel as usize;
+        let row_bytes = width * bpp;
+
+        for row in 0..height {
+            let dst_row = dst.add(row * dst_stride);
+            if self.native {
+                let src_row = buffer.as_ptr().add(row * width) as *const u8;
+                std::ptr::copy_nonoverlapping(src_row, dst_row, row_bytes);
+                continue;
+            }
+
+            for col in 0..width {
+                let packed = self.pack(buffer[row * width + col]).to_le_bytes();
+                std::ptr::copy_nonoverlapping(packed.as_ptr(), dst_row.add(col * bpp), bpp);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a `width` x `height` buffer into a freshly allocated, padded destination and
+    /// return it, so tests can inspect where each byte landed.
+    fn write_to_vec(format: &PixelFormat, buffer: &[u32], width: usize, height: usize) -> Vec<u8> {
+        let stride = format.stride(width);
+        let mut dst = vec![0xaau8; stride * height];
+        unsafe {
+            format.write(buffer, width, height, stride, dst.as_mut_ptr());
+        }
+        dst
+    }
+
+    #[test]
+    fn detect_picks_bytes_per_pixel_from_depth() {
+        assert_eq!(PixelFormat::detect(0, 0, 0, 1, 8).bytes_per_pixel(), 1);
+        assert_eq!(PixelFormat::detect(0, 0, 0, 8, 8).bytes_per_pixel(), 1);
+        assert_eq!(PixelFormat::detect(0, 0, 0, 9, 16).bytes_per_pixel(), 2);
+        assert_eq!(PixelFormat::detect(0, 0, 0, 16, 16).bytes_per_pixel(), 2);
+        assert_eq!(PixelFormat::detect(0, 0, 0, 24, 32).bytes_per_pixel(), 4);
+        assert_eq!(PixelFormat::detect(0, 0, 0, 32, 32).bytes_per_pixel(), 4);
+    }
+
+    #[test]
+    fn detect_recognises_native_bgra8888() {
+        let format = PixelFormat::detect(0x00ff0000, 0x0000ff00, 0x000000ff, 24, 32);
+        assert!(format.is_native());
+    }
+
+    #[test]
+    fn write_native_copies_bytes_as_is() {
+        let format = PixelFormat::detect(0x00ff0000, 0x0000ff00, 0x000000ff, 24, 32);
+        let buffer = [0x00123456u32, 0x00abcdefu32];
+        let dst = write_to_vec(&format, &buffer, 2, 1);
+        let mut expected = 0x00123456u32.to_le_bytes().to_vec();
+        expected.extend(0x00abcdefu32.to_le_bytes());
+        assert_eq!(dst.as_slice(), expected.as_slice());
+    }
+
+    #[test]
+    fn pack_rgb565() {
+        // 5 red / 6 green / 5 blue bits, as used by 16-bit TrueColor visuals.
+        let format = PixelFormat::detect(0xf800, 0x07e0, 0x001f, 16, 16);
+        assert!(!format.is_native());
+
+        let red = write_to_vec(&format, &[0x00ff0000], 1, 1);
+        assert_eq!(red.as_slice(), &0xf800u16.to_le_bytes());
+
+        let green = write_to_vec(&format, &[0x0000ff00], 1, 1);
+        assert_eq!(green.as_slice(), &0x07e0u16.to_le_bytes());
+
+        let blue = write_to_vec(&format, &[0x000000ff], 1, 1);
+        assert_eq!(blue.as_slice(), &0x001fu16.to_le_bytes());
+    }
+
+    #[test]
+    fn pack_rgb555() {
+        // 5 bits per channel, packed into the low 15 bits.
+        let format = PixelFormat::detect(0x7c00, 0x03e0, 0x001f, 15, 16);
+        let white = write_to_vec(&format, &[0x00ffffff], 1, 1);
+        assert_eq!(white.as_slice(), &0x7fffu16.to_le_bytes());
+    }
+
+    #[test]
+    fn pack_contributes_nothing_for_a_zero_mask_channel() {
+        // A palette-based visual with no direct RGB channels: every mask is zero.
+        let format = PixelFormat::detect(0, 0, 0, 8, 8);
+        let written = write_to_vec(&format, &[0x00ffffff], 1, 1);
+        assert_eq!(written.as_slice(), &[0u8]);
+    }
+
+    #[test]
+    fn stride_pads_rows_to_the_scanline_pad() {
+        // 8-bit pixels on a server that pads scanlines to 32 bits: 3 tightly-packed pixels
+        // would be 3 bytes, but the real row stride must round up to 4. The blue channel takes
+        // the full low byte, so each pixel's blue sample lands in the output unchanged.
+        let format = PixelFormat::detect(0, 0, 0x000000ff, 8, 32);
+        assert_eq!(format.stride(3), 4);
+
+        let buffer = [0x00010101u32, 0x00020202, 0x00030303, 0x00040404, 0x00050505, 0x00060606];
+        let dst = write_to_vec(&format, &buffer, 3, 2);
+
+        // Row 0 occupies the first 3 bytes, row 1 starts at the padded offset of 4, not 3.
+        assert_eq!(dst.len(), 8);
+        assert_eq!(&dst[0..3], [0x01, 0x02, 0x03]);
+        assert_eq!(&dst[4..7], [0x04, 0x05, 0x06]);
+    }
+}
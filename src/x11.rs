@@ -1,20 +1,31 @@
 //! Implementation of software buffering for X11.
 //!
 //! This module converts the input buffer into an XImage and then sends it over the wire to be
-//! drawn. A more effective implementation would use shared memory instead of the wire. In
-//! addition, we may also want to blit to a pixmap instead of a window.
+//! drawn. Where the server supports the MIT-SHM extension, the image is passed through shared
+//! memory instead, and the wire is only used as a fallback.
+//!
+//! Optionally, the buffer can be blit to a server-side pixmap instead of the window directly;
+//! see [`X11Impl::set_pixmap_mode`].
 
+use crate::pixel_format::PixelFormat;
 use crate::SwBufError;
 use nix::libc::{shmget, shmat, IPC_PRIVATE, shmctl, shmdt, IPC_RMID};
 use raw_window_handle::{XlibDisplayHandle, XlibWindowHandle};
 
 use std::io;
 use std::mem;
-use std::os::raw::{c_char, c_uint};
+use std::os::raw::{c_char, c_int, c_uint};
 use std::ptr::{null_mut, NonNull};
 
-use x11_dl::xlib::{Display, Visual, Xlib, ZPixmap, GC};
-use x11_dl::xshm::{Xext as XShm, XShmSegmentInfo};
+use x11_dl::xlib::{
+    Display, Drawable, False, Pixmap, True, Visual, XEvent, XPixmapFormatValues, XPointer, Xlib,
+    ZPixmap, GC,
+};
+use x11_dl::xshm::{Xext as XShm, ShmCompletion, XShmCompletionEvent, XShmSegmentInfo};
+
+/// The number of shared memory segments to keep in flight, so that the client can write into
+/// one while the server is still reading the other.
+const BUFFER_COUNT: usize = 2;
 
 /// The handle to an X11 drawing context.
 pub struct X11Impl {
@@ -38,6 +49,30 @@ pub struct X11Impl {
 
     /// The depth (bits per pixel) of the drawing context.
     depth: i32,
+
+    /// Whether we should draw into [`Self::pixmap`] instead of the window directly; see
+    /// [`Self::set_pixmap_mode`].
+    pixmap_mode: bool,
+
+    /// The backing pixmap used in pixmap mode, and the size it was last allocated at.
+    pixmap: Option<PixmapBuffer>,
+
+    /// How to repack the caller's 32-bit BGRA buffer for the window's actual visual, computed
+    /// once from its masks and depth.
+    pixel_format: PixelFormat,
+}
+
+
+/// A server-side pixmap kept around so the window can be repainted (e.g. on `Expose`) without
+/// the caller having to resubmit a buffer, and so the shared segment's damage region stays
+/// isolated from the window's.
+struct PixmapBuffer {
+    /// The pixmap itself.
+    pixmap: Pixmap,
+
+    /// The size the pixmap was created at.
+    width: u16,
+    height: u16,
 }
 
 /// SHM-specific information.
@@ -45,8 +80,28 @@ struct ShmExtension {
     /// The shared memory library.
     xshm: XShm,
 
-    /// Pointer to the shared memory segment, as well as its current size.
-    shmseg: Option<ShmSegment>,
+    /// The event type used to signal that the server is done reading a segment, queried once
+    /// with `XShmGetEventBase`.
+    completion_event: i32,
+
+    /// The double-buffered segments that we round-robin between.
+    buffers: [ShmBuffer; BUFFER_COUNT],
+
+    /// The index of the buffer to write into on the next `set_buffer` call.
+    next: usize,
+}
+
+/// One of the double-buffered shared memory segments.
+struct ShmBuffer {
+    /// The underlying shared memory segment, if it has been allocated yet.
+    seg: Option<ShmSegment>,
+
+    /// The segment info the server attached, valid (and kept attached) for as long as `seg` is
+    /// `Some`. Its `shmseg` field is how we match up completion events to this buffer.
+    info: XShmSegmentInfo,
+
+    /// Whether the server may still be reading this segment; if so, we must not write into it.
+    in_flight: bool,
 }
 
 /// An SHM segment.
@@ -61,6 +116,85 @@ struct ShmSegment {
     size: usize,
 }
 
+impl ShmBuffer {
+    /// An empty buffer with no backing segment yet.
+    fn empty() -> Self {
+        Self {
+            seg: None,
+            info: unsafe { mem::zeroed() },
+            in_flight: false,
+        }
+    }
+
+    /// Make sure this buffer has a segment attached that's at least `size` bytes, (re)creating
+    /// it if necessary.
+    ///
+    /// The caller must have already confirmed this buffer isn't [`Self::in_flight`] (waiting for
+    /// its completion event if necessary) before calling this: detaching a segment the server is
+    /// still reading from is exactly the corruption this whole double-buffering scheme exists to
+    /// prevent.
+    unsafe fn ensure(&mut self, xshm: &XShm, xlib: &Xlib, display: *mut Display, size: usize) -> io::Result<()> {
+        if let Some(seg) = &self.seg {
+            if seg.size >= size {
+                return Ok(());
+            }
+
+            // The old segment is too small; detach it before we replace it. Clear `seg` and
+            // `in_flight` immediately: if allocating the replacement below fails, this buffer must
+            // not still look attached or in flight for a shmseg the server no longer has attached,
+            // since no completion event for it will ever arrive (which would otherwise wedge a
+            // later blocking wait).
+            (xshm.XShmDetach)(display, &mut self.info);
+            self.seg = None;
+            self.in_flight = false;
+        }
+
+        let seg = ShmSegment::new(size)?;
+
+        let mut info: XShmSegmentInfo = mem::zeroed();
+        info.shmid = seg.id;
+        info.shmaddr = seg.ptr.as_ptr();
+        info.readOnly = 0;
+
+        if (xshm.XShmAttach)(display, &mut info) == 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "XShmAttach failed"));
+        }
+
+        // Block until the server has actually mapped the segment; it needs to see this before
+        // we start writing pixels that it might read as part of a subsequent XShmPutImage.
+        (xlib.XSync)(display, False);
+
+        self.seg = Some(seg);
+        self.info = info;
+        self.in_flight = false;
+        Ok(())
+    }
+}
+
+/// `XIfEvent` predicate used to block for a specific SHM completion event without dequeuing
+/// (and thus stealing) any other event type the caller's own event loop still needs to see.
+unsafe extern "C" fn is_completion_event(_display: *mut Display, event: *mut XEvent, arg: XPointer) -> c_int {
+    let target = *(arg as *const i32);
+    ((*event).type_ == target) as c_int
+}
+
+/// List the server's supported pixmap formats (one per depth) as `(depth, scanline_pad)` pairs,
+/// so we can read off each depth's real scanline pad instead of assuming one.
+unsafe fn list_pixmap_formats(lib: &Xlib, display: *mut Display) -> Vec<(c_int, c_int)> {
+    let mut count: c_int = 0;
+    let formats = (lib.XListPixmapFormats)(display, &mut count);
+    if formats.is_null() {
+        return Vec::new();
+    }
+
+    let result = std::slice::from_raw_parts(formats, count as usize)
+        .iter()
+        .map(|format: &XPixmapFormatValues| (format.depth, format.scanline_pad))
+        .collect();
+    (lib.XFree)(formats as *mut _);
+    result
+}
+
 impl X11Impl {
     /// Create a new `X11Impl` from a `XlibWindowHandle` and `XlibDisplayHandle`.
     ///
@@ -105,6 +239,26 @@ impl X11Impl {
         let visual = (lib.XDefaultVisual)(display_handle.display as *mut Display, screen);
         let depth = (lib.XDefaultDepth)(display_handle.display as *mut Display, screen);
 
+        // Look up this depth's real scanline pad: the server pads every row of a pixmap image
+        // to a multiple of this many bits (typically 32, but not guaranteed for the shallower
+        // depths our conversion targets), and `XShmCreateImage` expects the segment's data laid
+        // out to that stride, not packed as `width * bytes_per_pixel`.
+        let scanline_pad = list_pixmap_formats(&lib, display_handle.display as *mut Display)
+            .into_iter()
+            .find(|(format_depth, _)| *format_depth == depth)
+            .map(|(_, scanline_pad)| scanline_pad as u32)
+            .unwrap_or(32);
+
+        // Work out, once, whether this visual's layout matches our input buffers or needs
+        // conversion.
+        let pixel_format = PixelFormat::detect(
+            (*visual).red_mask as u32,
+            (*visual).green_mask as u32,
+            (*visual).blue_mask as u32,
+            depth as u32,
+            scanline_pad,
+        );
+
         // See if we can load the XShm extension.
         let xshm = XShm::open()
             .ok()
@@ -114,59 +268,292 @@ impl X11Impl {
             window_handle,
             display_handle,
             xlib: lib,
-            xshm: xshm.map(|xshm| ShmExtension { xshm, shmseg: None }),
+            xshm: xshm.map(|xshm| {
+                // Query once, up front: the completion event type doesn't change for the
+                // lifetime of the display connection.
+                let completion_event =
+                    (xshm.XShmGetEventBase)(display_handle.display as *mut Display) as i32
+                        + ShmCompletion;
+                ShmExtension {
+                    xshm,
+                    completion_event,
+                    buffers: [ShmBuffer::empty(), ShmBuffer::empty()],
+                    next: 0,
+                }
+            }),
             gc,
             visual,
             depth,
+            pixmap_mode: false,
+            pixmap: None,
+            pixel_format,
         })
     }
 
+    /// Enable or disable drawing into a backing pixmap instead of the window directly.
+    ///
+    /// While enabled, [`Self::set_buffer`] draws into the pixmap and then copies it onto the
+    /// window; the window can be repainted from the pixmap at any time with [`Self::redraw`],
+    /// e.g. in response to an `Expose` event, without the caller resubmitting a buffer.
+    pub(crate) fn set_pixmap_mode(&mut self, enabled: bool) {
+        self.pixmap_mode = enabled;
+        if !enabled {
+            self.free_pixmap();
+        }
+    }
+
+    /// Repaint the window from the backing pixmap, if pixmap mode is enabled and a pixmap has
+    /// been drawn into at least once.
+    pub(crate) unsafe fn redraw(&mut self) {
+        if let Some(pixmap) = &self.pixmap {
+            (self.xlib.XCopyArea)(
+                self.display_handle.display as *mut Display,
+                pixmap.pixmap,
+                self.window_handle.window,
+                self.gc,
+                0,
+                0,
+                pixmap.width as c_uint,
+                pixmap.height as c_uint,
+                0,
+                0,
+            );
+        }
+    }
+
+    /// The drawable that frames should be rendered into: the backing pixmap in pixmap mode,
+    /// the window otherwise.
+    unsafe fn ensure_draw_target(&mut self, width: u16, height: u16) -> io::Result<Drawable> {
+        if !self.pixmap_mode {
+            return Ok(self.window_handle.window);
+        }
+
+        // (Re)create the pixmap if it doesn't exist yet, or if it's the wrong size. The shared
+        // segments are not reallocated here: `ShmBuffer::ensure` regrows each one separately,
+        // and only when it's too small for the new size.
+        let needs_recreate = match &self.pixmap {
+            Some(pixmap) => pixmap.width != width || pixmap.height != height,
+            None => true,
+        };
+
+        if needs_recreate {
+            self.free_pixmap();
+
+            let pixmap = (self.xlib.XCreatePixmap)(
+                self.display_handle.display as *mut Display,
+                self.window_handle.window,
+                width as c_uint,
+                height as c_uint,
+                self.depth as c_uint,
+            );
+
+            if pixmap == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "XCreatePixmap failed",
+                ));
+            }
+
+            self.pixmap = Some(PixmapBuffer {
+                pixmap,
+                width,
+                height,
+            });
+        }
+
+        Ok(self.pixmap.as_ref().unwrap().pixmap)
+    }
+
+    /// Free the backing pixmap, if one has been allocated.
+    unsafe fn free_pixmap(&mut self) {
+        if let Some(pixmap) = self.pixmap.take() {
+            (self.xlib.XFreePixmap)(self.display_handle.display as *mut Display, pixmap.pixmap);
+        }
+    }
+
     pub(crate) unsafe fn set_buffer(&mut self, buffer: &[u32], width: u16, height: u16) {
         if self.shm_set(buffer, width, height).is_err() {
+            // Don't keep retrying XShm every frame once it's failed once: something about this
+            // display connection or window doesn't support it, and that isn't going to change
+            // mid-session. Fall back to `XPutImage` for the rest of this `X11Impl`'s lifetime.
+            self.xshm = None;
             self.fallback_set(buffer, width, height);
         }
+        self.redraw();
     }
 
     /// Set the buffer to the given image using shared memory.
+    ///
+    /// This never blocks on the round trip to the server unless both of the double-buffered
+    /// segments are still in flight; the critical invariant is that we never write into a
+    /// segment the server might still be reading from.
     unsafe fn shm_set(&mut self, buffer: &[u32], width: u16, height: u16) -> io::Result<()> {
+        let target = self.ensure_draw_target(width, height)?;
+        let display = self.display_handle.display as *mut Display;
+        let xlib = &self.xlib;
         let shm_ext = match self.xshm.as_mut() {
             Some(shm_ext) => shm_ext,
             None => return Err(io::Error::new(io::ErrorKind::Other, "XShm not available")),
         };
 
-        // Get the size of the shared memory segment.
-        let shmseg_size = (width as usize)
+        // Get the size of the shared memory segment, in the window's actual pixel format. This
+        // is `stride * height`, not `width * height * bytes_per_pixel`: the server pads every
+        // scanline out to its format's scanline pad, which can round `width` up.
+        let stride = self.pixel_format.stride(width as usize);
+        let shmseg_size = stride
             .checked_mul(height as usize)
-            .and_then(|size| size.checked_mul(4))
-            .expect("Buffer size overflow");
-
-        // Create the shared memory segment if it doesn't exist, or if it's the wrong size.
-        let shmseg = match &mut shm_ext.shmseg {
-            None => shm_ext.shmseg.insert(ShmSegment::new(shmseg_size)?),
-            Some(ref shmseg) if shmseg.size < shmseg_size => {
-                shm_ext.shmseg.insert(ShmSegment::new(shmseg_size)?)
-            }
-            Some(shmseg) => shmseg,
-        };
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "buffer size overflow"))?;
+
+        // Opportunistically recycle any buffer whose completion event has already arrived,
+        // without blocking.
+        Self::drain_completions(xlib, display, shm_ext, None);
+
+        // Pick the next buffer to write into, alternating each call.
+        let idx = shm_ext.next;
+        shm_ext.next = (idx + 1) % BUFFER_COUNT;
+
+        // If the buffer we're about to reuse is still being read by the server, we have no
+        // choice but to block until its completion event shows up. This has to happen *before*
+        // `ensure()`: growing or replacing the segment detaches (and frees) the old one, which
+        // we must never do while the server might still be reading from it.
+        if shm_ext.buffers[idx].in_flight {
+            Self::drain_completions(xlib, display, shm_ext, Some(idx));
+        }
+
+        shm_ext.buffers[idx].ensure(&shm_ext.xshm, xlib, display, shmseg_size)?;
+
+        let buf = &shm_ext.buffers[idx];
+        let seg = buf.seg.as_ref().expect("segment was just ensured");
 
-        // Create the basic image.
-        let mut seg: XShmSegmentInfo = mem::zeroed();
+        // Create the image; this is cheap local bookkeeping, unlike the segment attach/detach.
+        let mut info = buf.info;
         let image = (shm_ext.xshm.XShmCreateImage)(
-            self.display_handle.display as *mut Display,
+            display,
             self.visual,
             self.depth as u32,
             ZPixmap,
-            shmseg.ptr.as_ptr(),
-            &mut seg,
+            seg.ptr.as_ptr(),
+            &mut info,
             width as u32,
             height as u32,
         );
 
+        if image.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "XShmCreateImage returned a null image",
+            ));
+        }
+
+        // Convert (if needed) and copy the caller's buffer into the shared segment; `image.data`
+        // points at `seg.ptr`. Written at `stride`, the same padded row size the segment was
+        // sized for above, not a tightly packed `width * bytes_per_pixel`.
+        self.pixel_format
+            .write(buffer, width as usize, height as usize, stride, (*image).data as *mut u8);
+
+        // Ask the server to draw the segment's contents to the draw target, and to notify us
+        // with a completion event once it's done reading instead of making us wait for it here.
+        (shm_ext.xshm.XShmPutImage)(
+            display,
+            target,
+            self.gc,
+            image,
+            0,
+            0,
+            0,
+            0,
+            width as c_uint,
+            height as c_uint,
+            True,
+        );
+
+        shm_ext.buffers[idx].in_flight = true;
+
+        // Tear down the local image wrapper without freeing the segment memory, which we keep
+        // attached and reuse on the next frame.
+        (*image).data = null_mut();
+        (self.xlib.XDestroyImage)(image);
+
         Ok(())
     }
 
+    /// Drain pending SHM completion events, marking the buffers they refer to as no longer in
+    /// flight.
+    ///
+    /// If `wait_for` is `Some(idx)`, this blocks (via `XIfEvent`, filtered to just the
+    /// completion event type) until buffer `idx` is no longer in flight. We can't use
+    /// `XNextEvent` here: this library doesn't own the connection's event loop, so blindly
+    /// dequeuing the next event of *any* type would steal input/configure/expose events meant
+    /// for the caller. Otherwise, when not blocking, this only consumes events that are already
+    /// queued (via `XCheckTypedEvent`) and returns immediately.
+    unsafe fn drain_completions(
+        xlib: &Xlib,
+        display: *mut Display,
+        shm_ext: &mut ShmExtension,
+        wait_for: Option<usize>,
+    ) {
+        loop {
+            let mut raw_event: XEvent = mem::zeroed();
+            let have_event = match wait_for {
+                Some(_) => {
+                    let mut target = shm_ext.completion_event;
+                    (xlib.XIfEvent)(
+                        display,
+                        &mut raw_event,
+                        Some(is_completion_event),
+                        &mut target as *mut i32 as XPointer,
+                    );
+                    true
+                }
+                None => {
+                    (xlib.XCheckTypedEvent)(display, shm_ext.completion_event, &mut raw_event) != 0
+                }
+            };
+
+            if !have_event {
+                break;
+            }
+
+            let completion: XShmCompletionEvent = mem::transmute(raw_event);
+            for buf in shm_ext.buffers.iter_mut() {
+                if buf.in_flight && buf.info.shmseg == completion.shmseg {
+                    buf.in_flight = false;
+                }
+            }
+
+            if let Some(idx) = wait_for {
+                if !shm_ext.buffers[idx].in_flight {
+                    break;
+                }
+            }
+        }
+    }
+
     /// Fall back to using `XPutImage` to draw the buffer.
     unsafe fn fallback_set(&mut self, buffer: &[u32], width: u16, height: u16) {
+        // If we can't (re)allocate the pixmap, just draw straight to the window for this frame.
+        let target = self
+            .ensure_draw_target(width, height)
+            .unwrap_or(self.window_handle.window);
+
+        let bytes_per_pixel = self.pixel_format.bytes_per_pixel() as i32;
+
+        // On the fast path the buffer is already in the window's native layout and can be
+        // handed to Xlib as-is; otherwise we need an intermediate, converted copy.
+        let mut converted;
+        let data = if self.pixel_format.is_native() {
+            buffer.as_ptr() as *mut c_char
+        } else {
+            // Tightly packed: we declare this same stride to `XCreateImage` below, and Xlib
+            // repacks from it into whatever the wire actually needs, unlike the SHM path where
+            // the server reads the bytes directly.
+            let stride = width as usize * self.pixel_format.bytes_per_pixel() as usize;
+            converted = vec![0u8; stride * height as usize];
+            self.pixel_format
+                .write(buffer, width as usize, height as usize, stride, converted.as_mut_ptr());
+            converted.as_mut_ptr() as *mut c_char
+        };
+
         // Create the image from the buffer.
         let image = (self.xlib.XCreateImage)(
             self.display_handle.display as *mut Display,
@@ -174,17 +561,17 @@ impl X11Impl {
             self.depth as u32,
             ZPixmap,
             0,
-            (buffer.as_ptr()) as *mut c_char,
+            data,
             width as u32,
             height as u32,
-            32,
-            (width * 4) as i32,
+            bytes_per_pixel * 8,
+            (width as i32) * bytes_per_pixel,
         );
 
-        // Draw the image to the window.
+        // Draw the image to the draw target.
         (self.xlib.XPutImage)(
             self.display_handle.display as *mut Display,
-            self.window_handle.window,
+            target,
             self.gc,
             image,
             0,
@@ -201,6 +588,27 @@ impl X11Impl {
     }
 }
 
+impl Drop for X11Impl {
+    fn drop(&mut self) {
+        unsafe {
+            // Detach every attached SHM segment from the server before `ShmSegment`'s own
+            // `Drop` runs: that only undoes the client side (`shmdt`/`shmctl`), not
+            // `XShmAttach`, so without this every segment attached over this connection's
+            // lifetime would stay attached on the server after we've gone away.
+            if let Some(shm_ext) = &mut self.xshm {
+                let display = self.display_handle.display as *mut Display;
+                for buf in shm_ext.buffers.iter_mut() {
+                    if buf.seg.is_some() {
+                        (shm_ext.xshm.XShmDetach)(display, &mut buf.info);
+                    }
+                }
+            }
+
+            self.free_pixmap();
+        }
+    }
+}
+
 impl ShmSegment {
     /// Create a new `ShmSegment` with the given size.
     fn new(size: usize) -> io::Result<Self> {
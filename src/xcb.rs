@@ -0,0 +1,358 @@
+//! Implementation of software buffering for X11 via XCB.
+//!
+//! This mirrors `x11.rs`, but talks to the server through `x11rb` instead of linking `x11-dl`'s
+//! Xlib bindings, for callers that only have an `XcbDisplayHandle`/`XcbWindowHandle` (going
+//! through Xlib's XCB shim from there would be lossy). Shared memory is used when the server
+//! advertises the MIT-SHM extension, falling back to `put_image` over the socket otherwise.
+
+use crate::pixel_format::PixelFormat;
+use crate::SwBufError;
+use nix::libc::{shmat, shmctl, shmdt, shmget, IPC_PRIVATE, IPC_RMID};
+use raw_window_handle::{XcbDisplayHandle, XcbWindowHandle};
+
+use std::io;
+use std::ptr::NonNull;
+
+use x11rb::connection::Connection;
+use x11rb::protocol::shm::{self, ConnectionExt as _};
+use x11rb::protocol::xproto::{
+    ConnectionExt as _, CreateGCAux, Gcontext, ImageFormat, Visualid, Visualtype, Window,
+};
+use x11rb::xcb_ffi::XCBConnection;
+
+/// The handle to an X11 drawing context, backed by XCB.
+pub struct XcbImpl {
+    /// The XCB connection, wrapping the caller's raw `xcb_connection_t`.
+    connection: XCBConnection,
+
+    /// The window to draw into.
+    window: Window,
+
+    /// The graphics context for drawing.
+    gc: Gcontext,
+
+    /// The depth of the window, in bits (not necessarily its bits per pixel on the wire).
+    depth: u8,
+
+    /// How to convert a caller-supplied buffer into this window's actual visual.
+    pixel_format: PixelFormat,
+
+    /// Whether the server advertises the MIT-SHM extension, and the shared memory segment we
+    /// use to talk to it, if so.
+    xshm: Option<ShmState>,
+}
+
+/// SHM-specific information.
+struct ShmState {
+    /// The shared memory segment, as well as its current size.
+    shmseg: Option<ShmSegment>,
+}
+
+/// An SHM segment, identified on the XCB side by an `xid` handed out with `generate_id`.
+struct ShmSegment {
+    /// The System V shared memory segment ID.
+    id: i32,
+
+    /// The shared memory segment pointer.
+    ptr: NonNull<i8>,
+
+    /// The size of the shared memory segment.
+    size: usize,
+
+    /// The XID this segment is attached to the server under.
+    xid: u32,
+}
+
+impl XcbImpl {
+    /// Create a new `XcbImpl` from a `XcbWindowHandle` and `XcbDisplayHandle`.
+    ///
+    /// # Safety
+    ///
+    /// The `XcbWindowHandle` and `XcbDisplayHandle` must be valid, and the `xcb_connection_t`
+    /// they reference must outlive this struct.
+    pub unsafe fn new(
+        window_handle: XcbWindowHandle,
+        display_handle: XcbDisplayHandle,
+    ) -> Result<Self, SwBufError> {
+        if display_handle.connection.is_null() {
+            return Err(SwBufError::IncompleteDisplayHandle);
+        }
+
+        if window_handle.window == 0 {
+            return Err(SwBufError::IncompleteWindowHandle);
+        }
+
+        // Wrap the caller's connection; `false` means we don't take ownership of it, since it's
+        // the caller's responsibility to close it.
+        let connection = XCBConnection::from_raw_xcb_connection(
+            display_handle.connection as *mut _,
+            false,
+        )
+        .map_err(|e| {
+            SwBufError::PlatformError(Some("Failed to wrap xcb_connection_t".into()), Some(Box::new(e)))
+        })?;
+
+        let window = window_handle.window;
+
+        // Figure out the window's depth so we know how to build the image later.
+        let geometry = connection
+            .get_geometry(window)
+            .and_then(|cookie| cookie.reply())
+            .map_err(|e| {
+                SwBufError::PlatformError(Some("Failed to query window geometry".into()), Some(Box::new(e)))
+            })?;
+        let depth = geometry.depth;
+
+        // Look up the window's visual so we know how to repack pixels for it, same as we do from
+        // the `Visual` Xlib hands us directly in `x11.rs`.
+        let attrs = connection
+            .get_window_attributes(window)
+            .and_then(|cookie| cookie.reply())
+            .map_err(|e| {
+                SwBufError::PlatformError(Some("Failed to query window attributes".into()), Some(Box::new(e)))
+            })?;
+        let visual = find_visualtype(&connection, attrs.visual).ok_or_else(|| {
+            SwBufError::PlatformError(Some("Failed to find the window's visual type".into()), None)
+        })?;
+
+        // Look up this depth's real scanline pad: the server pads every row of a pixmap image
+        // to a multiple of this many bits (typically 32, but not guaranteed for the shallower
+        // depths our conversion targets), and `shm_put_image` reads the segment at that stride,
+        // not packed as `width * bytes_per_pixel`.
+        let scanline_pad = connection
+            .setup()
+            .pixmap_formats
+            .iter()
+            .find(|format| format.depth == depth)
+            .map(|format| format.scanline_pad as u32)
+            .unwrap_or(32);
+
+        let pixel_format = PixelFormat::detect(
+            visual.red_mask,
+            visual.green_mask,
+            visual.blue_mask,
+            depth as u32,
+            scanline_pad,
+        );
+
+        // Create a graphics context to draw with, same as `XDefaultGC` gives us on the Xlib
+        // side.
+        let gc = connection.generate_id().map_err(|e| {
+            SwBufError::PlatformError(Some("Failed to allocate a graphics context id".into()), Some(Box::new(e)))
+        })?;
+        connection
+            .create_gc(gc, window, &CreateGCAux::new())
+            .and_then(|cookie| cookie.check())
+            .map_err(|e| {
+                SwBufError::PlatformError(Some("Failed to create a graphics context".into()), Some(Box::new(e)))
+            })?;
+
+        // See if the server supports MIT-SHM.
+        let xshm = connection
+            .shm_query_version()
+            .and_then(|cookie| cookie.reply())
+            .is_ok();
+
+        Ok(Self {
+            connection,
+            window,
+            gc,
+            depth,
+            pixel_format,
+            xshm: xshm.then_some(ShmState { shmseg: None }),
+        })
+    }
+
+    pub(crate) unsafe fn set_buffer(&mut self, buffer: &[u32], width: u16, height: u16) {
+        if self.shm_set(buffer, width, height).is_err() {
+            // Don't keep retrying XShm every frame once it's failed once, mirroring the Xlib
+            // backend (`x11.rs`): something about this connection or window doesn't support it,
+            // and that isn't going to change mid-session. Fall back to `put_image` for the rest
+            // of this `XcbImpl`'s lifetime.
+            self.disable_shm();
+            self.fallback_set(buffer, width, height);
+        }
+    }
+
+    /// Detach the current SHM segment from the server, if any, and stop using SHM for the rest
+    /// of this connection's lifetime.
+    fn disable_shm(&mut self) {
+        if let Some(xshm) = self.xshm.take() {
+            if let Some(seg) = xshm.shmseg {
+                let _ = self.connection.shm_detach(seg.xid);
+            }
+        }
+    }
+
+    /// Set the buffer to the given image using shared memory.
+    fn shm_set(&mut self, buffer: &[u32], width: u16, height: u16) -> io::Result<()> {
+        let connection = &self.connection;
+        let xshm = self
+            .xshm
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "XShm not available"))?;
+
+        // Get the size of the shared memory segment, in the window's actual pixel format. This
+        // is `stride * height`, not `width * height * bytes_per_pixel`: the server pads every
+        // scanline out to its format's scanline pad, which can round `width` up.
+        let stride = self.pixel_format.stride(width as usize);
+        let shmseg_size = stride
+            .checked_mul(height as usize)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "buffer size overflow"))?;
+
+        let shmseg = match &xshm.shmseg {
+            Some(shmseg) if shmseg.size >= shmseg_size => xshm.shmseg.as_ref().unwrap(),
+            _ => {
+                if let Some(old) = xshm.shmseg.take() {
+                    let _ = connection.shm_detach(old.xid);
+                }
+                let xid = connection
+                    .generate_id()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                let shmseg = ShmSegment::new(shmseg_size, xid)?;
+                connection
+                    .shm_attach(xid, shmseg.id as u32, false)
+                    .and_then(|cookie| cookie.check())
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+                xshm.shmseg.insert(shmseg)
+            }
+        };
+
+        // Repack the caller's buffer into the shared segment before asking the server to read
+        // it, converting to the window's actual visual unless it's already a native match.
+        // Written at `stride`, the same padded row size the segment was sized for above.
+        unsafe {
+            self.pixel_format
+                .write(buffer, width as usize, height as usize, stride, shmseg.ptr.as_ptr() as *mut u8);
+        }
+
+        connection
+            .shm_put_image(
+                self.window,
+                self.gc,
+                width,
+                height,
+                0,
+                0,
+                width,
+                height,
+                0,
+                0,
+                self.depth,
+                ImageFormat::Z_PIXMAP.into(),
+                false,
+                shmseg.xid,
+                0,
+            )
+            .and_then(|cookie| cookie.check())
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+        Ok(())
+    }
+
+    /// Fall back to sending the buffer over the wire with `put_image`.
+    fn fallback_set(&mut self, buffer: &[u32], width: u16, height: u16) {
+        // On the fast path the buffer is already in the window's native layout and can be sent
+        // as-is; otherwise we need an intermediate, converted copy.
+        let mut converted;
+        let bytes: &[u8] = if self.pixel_format.is_native() {
+            bytemuck_cast_slice(buffer)
+        } else {
+            // Padded: unlike Xlib's `XCreateImage`, `put_image` sends these bytes to the server
+            // as-is, so each row must already be padded out to the scanline pad.
+            let stride = self.pixel_format.stride(width as usize);
+            converted = vec![0u8; stride * height as usize];
+            unsafe {
+                self.pixel_format
+                    .write(buffer, width as usize, height as usize, stride, converted.as_mut_ptr());
+            }
+            &converted
+        };
+
+        let _ = self
+            .connection
+            .put_image(
+                ImageFormat::Z_PIXMAP,
+                self.window,
+                self.gc,
+                width,
+                height,
+                0,
+                0,
+                0,
+                self.depth,
+                bytes,
+            )
+            .and_then(|cookie| cookie.check());
+    }
+}
+
+impl Drop for XcbImpl {
+    fn drop(&mut self) {
+        // Detach the SHM segment and free the graphics context on the server: neither is
+        // covered by `ShmSegment`'s own `Drop` (client-side `shmdt`/`shmctl` only), and the GC
+        // has no other owner once this connection-scoped handle goes away.
+        self.disable_shm();
+        let _ = self.connection.free_gc(self.gc);
+    }
+}
+
+/// View a `[u32]` buffer as the raw bytes `put_image` expects.
+fn bytemuck_cast_slice(buffer: &[u32]) -> &[u8] {
+    // SAFETY: `u32` has no padding and any bit pattern is valid for `u8`.
+    unsafe {
+        std::slice::from_raw_parts(buffer.as_ptr() as *const u8, std::mem::size_of_val(buffer))
+    }
+}
+
+/// Find the `Visualtype` for a given `Visualid` by scanning every screen's allowed depths, as
+/// `x11rb` doesn't expose this lookup directly.
+fn find_visualtype(connection: &XCBConnection, visual_id: Visualid) -> Option<Visualtype> {
+    for screen in &connection.setup().roots {
+        for depth in &screen.allowed_depths {
+            for visual in &depth.visuals {
+                if visual.visual_id == visual_id {
+                    return Some(*visual);
+                }
+            }
+        }
+    }
+    None
+}
+
+impl ShmSegment {
+    /// Create a new `ShmSegment` with the given size, identified to the server as `xid`.
+    fn new(size: usize, xid: u32) -> io::Result<Self> {
+        unsafe {
+            let id = shmget(IPC_PRIVATE, size, 0o600);
+            if id == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            let ptr = shmat(id, std::ptr::null_mut(), 0);
+            let ptr = match NonNull::new(ptr as *mut i8) {
+                Some(ptr) => ptr,
+                None => {
+                    shmctl(id, IPC_RMID, std::ptr::null_mut());
+                    return Err(io::Error::last_os_error());
+                }
+            };
+
+            Ok(Self {
+                id,
+                ptr,
+                size,
+                xid,
+            })
+        }
+    }
+}
+
+impl Drop for ShmSegment {
+    fn drop(&mut self) {
+        unsafe {
+            shmdt(self.ptr.as_ptr() as _);
+            shmctl(self.id, IPC_RMID, std::ptr::null_mut());
+        }
+    }
+}